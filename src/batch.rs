@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{Args, OutFormat};
+use crate::processor::{
+    load_and_prepare_image, process_lossy_compression, process_png_compression,
+    resolve_auto_format,
+};
+
+/// Reports whether `input` should be treated as a batch of files rather than
+/// a single image: a directory, or a path containing glob wildcard characters.
+pub fn is_batch_input(input: &Path) -> bool {
+    input.is_dir() || input.to_string_lossy().contains(['*', '?', '['])
+}
+
+struct FileSummary {
+    original_bytes: u64,
+    final_bytes: u64,
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref(),
+        Some("jpg" | "jpeg" | "png" | "webp" | "gif" | "bmp" | "tiff" | "avif")
+    )
+}
+
+fn collect_dir_recursive(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("failed to read input directory: {:?}", dir))?
+    {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_dir_recursive(&path, paths)?;
+        } else if path.is_file() && is_supported_image(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn collect_input_paths(input: &Path) -> Result<Vec<PathBuf>> {
+    if input.is_dir() {
+        let mut paths = Vec::new();
+        collect_dir_recursive(input, &mut paths)?;
+        paths.sort();
+        Ok(paths)
+    } else {
+        let pattern = input.to_string_lossy();
+        glob::glob(&pattern)
+            .with_context(|| format!("invalid glob pattern: {pattern}"))?
+            .map(|entry| entry.context("failed to read glob match"))
+            .collect()
+    }
+}
+
+fn ext_for_format(fmt: OutFormat) -> &'static str {
+    match fmt {
+        // Resolved to a concrete format in `process_one` before this is called.
+        OutFormat::Auto => unreachable!("OutFormat::Auto must be resolved before use"),
+        OutFormat::Jpeg => "jpg",
+        OutFormat::Webp => "webp",
+        OutFormat::Png => "png",
+        OutFormat::Avif => "avif",
+    }
+}
+
+/// Mirrors `input`'s path relative to `base_input` under `output_dir`, so that
+/// files of the same name in different subdirectories (found by the
+/// recursive directory walk) don't collide. Falls back to a flat layout for
+/// glob-mode inputs, where `base_input` isn't an ancestor of `input`.
+fn output_path_for(input: &Path, base_input: &Path, output_dir: &Path, format: OutFormat) -> PathBuf {
+    let mut out = match input.strip_prefix(base_input) {
+        Ok(relative) if !relative.as_os_str().is_empty() => output_dir.join(relative),
+        _ => output_dir.join(input.file_name().unwrap_or_default()),
+    };
+    out.set_extension(ext_for_format(format));
+    out
+}
+
+fn process_one(input_path: &Path, args: &Args, target_bytes: u64) -> Result<FileSummary> {
+    let original_bytes = fs::metadata(input_path)
+        .with_context(|| format!("failed to stat input: {:?}", input_path))?
+        .len();
+
+    let mut file_args = args.clone();
+    file_args.input = input_path.to_path_buf();
+
+    let img = load_and_prepare_image(&file_args)?;
+
+    if file_args.format == OutFormat::Auto {
+        file_args.format = resolve_auto_format(&file_args.input, &img);
+    }
+    file_args.output = output_path_for(input_path, &args.input, &args.output, file_args.format);
+    if let Some(parent) = file_args.output.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create output directory: {:?}", parent))?;
+    }
+
+    let compression_info = if file_args.format == OutFormat::Png {
+        process_png_compression(img, &file_args, target_bytes)?
+    } else {
+        process_lossy_compression(img, &file_args, target_bytes)?
+    };
+
+    let final_bytes = fs::metadata(&file_args.output)
+        .with_context(|| format!("failed to stat output: {:?}", file_args.output))?
+        .len();
+
+    println!(
+        "✓ {:?} -> {:?}  {:.1}KB -> {:.1}KB  {}",
+        input_path,
+        file_args.output,
+        original_bytes as f64 / 1024.0,
+        final_bytes as f64 / 1024.0,
+        compression_info
+    );
+
+    Ok(FileSummary {
+        original_bytes,
+        final_bytes,
+    })
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let target_bytes = args.target_kb * 1024;
+    let paths = collect_input_paths(&args.input)?;
+
+    if paths.is_empty() {
+        eprintln!("No matching input images found for {:?}", args.input);
+        return Ok(());
+    }
+
+    fs::create_dir_all(&args.output)
+        .with_context(|| format!("failed to create output directory: {:?}", args.output))?;
+
+    eprintln!(
+        "Batch mode: {} files -> {:?} (target {:.1}KB, {:?} format)",
+        paths.len(),
+        args.output,
+        target_bytes as f64 / 1024.0,
+        args.format
+    );
+
+    let pool = match args.jobs {
+        Some(n) if n > 0 => ThreadPoolBuilder::new().num_threads(n).build(),
+        _ => ThreadPoolBuilder::new().build(),
+    }
+    .context("failed to build thread pool")?;
+
+    let results: Vec<Result<FileSummary>> =
+        pool.install(|| paths.par_iter().map(|path| process_one(path, args, target_bytes)).collect());
+
+    let mut succeeded = 0u32;
+    let mut over_target = 0u32;
+    let mut total_saved: i64 = 0;
+
+    for (path, result) in paths.iter().zip(results) {
+        match result {
+            Ok(summary) => {
+                total_saved += summary.original_bytes as i64 - summary.final_bytes as i64;
+                if summary.final_bytes <= target_bytes {
+                    succeeded += 1;
+                } else {
+                    over_target += 1;
+                }
+            }
+            Err(err) => {
+                eprintln!("✗ {:?} failed: {:#}", path, err);
+            }
+        }
+    }
+
+    eprintln!();
+    eprintln!(
+        "Batch summary: {} succeeded, {} over target, {} failed, {:.1}KB saved across {} files",
+        succeeded,
+        over_target,
+        paths.len() as u32 - succeeded - over_target,
+        total_saved as f64 / 1024.0,
+        paths.len()
+    );
+
+    Ok(())
+}
@@ -2,28 +2,67 @@ use clap::{Parser, ValueEnum};
 
 #[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
 pub enum OutFormat {
+    /// Pick JPEG/WebP for photographic sources, PNG for lossless/alpha sources
+    Auto,
     Jpeg,
     Webp,
     Png,
+    Avif,
 }
 
-#[derive(Parser, Debug)]
+// Resampling runs through `resize::resize_separable`: separable convolution
+// with per-axis coefficients computed once and cached per (source length,
+// destination length, filter) triple, so same-sized files in batch mode
+// reuse them instead of recomputing on every call (successive downscale
+// rounds of one file each resize to a new size and compute fresh ones). See `resize.rs` for why there's no explicit SIMD intrinsics
+// path (no Cargo.toml to add a SIMD crate to, and `std::simd` is nightly
+// only).
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum ResizeFilter {
+    Nearest,
+    /// Linear resampling; also accepted as `bilinear`
+    #[value(alias = "bilinear")]
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum JpegEncoderKind {
+    /// The standard baseline encoder from the `image` crate
+    Baseline,
+    /// mozjpeg's trellis-quantized encoder; smaller files at equal quality
+    Mozjpeg,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum GrayscaleMode {
+    /// Encode as grayscale only if the decoded image has no color information
+    Auto,
+    /// Always encode as grayscale, discarding any color information
+    Force,
+    /// Never encode as grayscale
+    Off,
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "resizer",
     about = "Compress an image to be <= target size (KB)"
 )]
 pub struct Args {
-    /// Input image path
+    /// Input image path, directory, or glob pattern (e.g. "photos/*.jpg") for batch mode
     pub input: std::path::PathBuf,
-    /// Output image path
+    /// Output image path, or output directory when `input` is a directory/glob
     pub output: std::path::PathBuf,
 
     /// Target size in KB (upper bound)
     #[arg(long)]
     pub target_kb: u64,
 
-    /// Output format: jpeg, webp, or png
-    #[arg(long, value_enum, default_value_t = OutFormat::Webp)]
+    /// Output format: auto (pick jpeg/webp vs png from the source), jpeg, webp, png, or avif
+    #[arg(long, value_enum, default_value_t = OutFormat::Auto)]
     pub format: OutFormat,
 
     /// Optional max width
@@ -48,4 +87,62 @@ pub struct Args {
     /// PNG compression level (0-9, higher = slower but smaller)
     #[arg(long, default_value_t = 6)]
     pub png_compression_level: u8,
+
+    /// AVIF encoding effort (0 = slowest/best compression, 10 = fastest)
+    #[arg(long, default_value_t = 4)]
+    pub avif_speed: u8,
+
+    /// oxipng post-encode optimization level (0-6, higher = smaller but slower)
+    #[arg(long, default_value_t = 2)]
+    pub oxipng_level: u8,
+
+    /// Use the slower Zopfli deflater in oxipng for maximum PNG compression
+    #[arg(long, default_value_t = false)]
+    pub zopfli: bool,
+
+    /// Max palette size to try when quantizing PNG colors to hit the target (2-256)
+    #[arg(long, default_value_t = 256)]
+    pub png_max_colors: u16,
+
+    /// Apply Floyd-Steinberg dithering when quantizing PNG colors
+    #[arg(long, default_value_t = false)]
+    pub dither: bool,
+
+    /// Grayscale encoding for PNG/JPEG: auto-detect, force, or disable
+    #[arg(long, value_enum, default_value_t = GrayscaleMode::Auto)]
+    pub grayscale: GrayscaleMode,
+
+    /// Max parallel jobs in batch/directory mode (default: number of CPUs)
+    #[arg(long)]
+    pub jobs: Option<usize>,
+
+    /// Resampling filter used for all downscaling operations
+    #[arg(long, value_enum, default_value_t = ResizeFilter::Lanczos3)]
+    pub filter: ResizeFilter,
+
+    /// JPEG encoder backend
+    #[arg(long, value_enum, default_value_t = JpegEncoderKind::Mozjpeg)]
+    pub jpeg_encoder: JpegEncoderKind,
+
+    /// Encode progressive JPEG (multiple scans); only supported by the mozjpeg encoder
+    #[arg(long, default_value_t = false)]
+    pub progressive: bool,
+
+    /// Stop the quality search early once within this many KB under target-kb
+    #[arg(long)]
+    pub tolerance_kb: Option<u64>,
+
+    /// Embed the source's ICC color profile in the output (JPEG and WebP
+    /// only; PNG and AVIF outputs are always written without one)
+    #[arg(long, default_value_t = false)]
+    pub keep_icc: bool,
 }
+
+// Every encoder here writes a fresh file from a raw pixel buffer rather than
+// copying the source container through, so there's no EXIF/XMP passthrough
+// to offer -- EXIF orientation is the one piece of metadata that matters
+// for correctness, and it's handled unconditionally in
+// `processor::load_and_prepare_image` by rotating the pixels themselves.
+// ICC is different: `processor::apply_keep_icc` patches a source profile
+// into already-encoded JPEG/WebP bytes after the fact, which is why
+// `--keep-icc` covers only those two formats today.
@@ -1,26 +1,79 @@
 use anyhow::{Context, Result, bail};
 use image::codecs::jpeg::JpegEncoder;
 use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
-use image::imageops::FilterType;
-use image::{DynamicImage, GenericImageView, ImageEncoder};
+use image::{DynamicImage, GenericImageView, ImageDecoder, ImageEncoder};
 use std::fs;
 use std::io::{Cursor, Write};
 use std::path::PathBuf;
 
-use crate::cli::{Args, OutFormat};
+use crate::cli::{Args, GrayscaleMode, JpegEncoderKind, OutFormat, ResizeFilter};
 
-pub fn encode(img: &DynamicImage, fmt: OutFormat, quality: u8) -> Result<Vec<u8>> {
+pub fn encode(
+    img: &DynamicImage,
+    fmt: OutFormat,
+    quality: u8,
+    avif_speed: u8,
+    jpeg_encoder: JpegEncoderKind,
+    progressive: bool,
+) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
     {
         let mut cursor = Cursor::new(&mut buf);
         match fmt {
+            OutFormat::Auto => {
+                bail!("OutFormat::Auto must be resolved via resolve_auto_format before encoding")
+            }
+            OutFormat::Jpeg if jpeg_encoder == JpegEncoderKind::Mozjpeg => {
+                // mozjpeg doesn't support alpha either; fall back to luma/rgb same as baseline
+                let grayscale = matches!(
+                    img,
+                    DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_)
+                );
+                let (w, h, pixels, color_space) = if grayscale {
+                    let luma = img.to_luma8();
+                    let (w, h) = luma.dimensions();
+                    (w, h, luma.into_raw(), mozjpeg::ColorSpace::JCS_GRAYSCALE)
+                } else {
+                    let rgb = img.to_rgb8();
+                    let (w, h) = rgb.dimensions();
+                    (w, h, rgb.into_raw(), mozjpeg::ColorSpace::JCS_RGB)
+                };
+
+                let mut comp = mozjpeg::Compress::new(color_space);
+                comp.set_size(w as usize, h as usize);
+                comp.set_quality(quality as f32);
+                if progressive {
+                    comp.set_progressive_mode();
+                }
+
+                let mut comp = comp
+                    .start_compress(Vec::new())
+                    .context("mozjpeg encode failed")?;
+                comp.write_scanlines(&pixels)
+                    .context("mozjpeg encode failed")?;
+                let encoded = comp.finish().context("mozjpeg encode failed")?;
+                cursor.write_all(&encoded).context("JPEG encode failed")?;
+            }
             OutFormat::Jpeg => {
-                // JPEG doesn't support alpha
-                let rgb = img.to_rgb8();
-                let (w, h) = rgb.dimensions();
+                // JPEG doesn't support alpha. `progressive` has no effect on this
+                // path (the baseline `image` encoder doesn't support it); the
+                // mismatch is warned about once at startup in `main`, not here,
+                // since this runs once per quality-search probe.
                 let enc = JpegEncoder::new_with_quality(&mut cursor, quality);
-                enc.write_image(rgb.as_raw(), w, h, image::ExtendedColorType::Rgb8)
-                    .context("JPEG encode failed")?;
+                if matches!(
+                    img,
+                    DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_)
+                ) {
+                    let luma = img.to_luma8();
+                    let (w, h) = luma.dimensions();
+                    enc.write_image(luma.as_raw(), w, h, image::ExtendedColorType::L8)
+                        .context("JPEG encode failed")?;
+                } else {
+                    let rgb = img.to_rgb8();
+                    let (w, h) = rgb.dimensions();
+                    enc.write_image(rgb.as_raw(), w, h, image::ExtendedColorType::Rgb8)
+                        .context("JPEG encode failed")?;
+                }
             }
             OutFormat::Webp => {
                 // WebP preserves alpha if present, otherwise converts to RGB
@@ -41,33 +94,94 @@ pub fn encode(img: &DynamicImage, fmt: OutFormat, quality: u8) -> Result<Vec<u8>
             }
             OutFormat::Png => {
                 let level = quality.min(9);
-                if img.color().has_alpha() {
+                let enc = PngEncoder::new_with_quality(
+                    &mut cursor,
+                    CompressionType::Level(level),
+                    PngFilterType::Adaptive,
+                );
+                match img {
+                    DynamicImage::ImageLumaA8(la) => {
+                        let (w, h) = la.dimensions();
+                        enc.write_image(la.as_raw(), w, h, image::ExtendedColorType::La8)
+                            .context("PNG encode failed")?;
+                    }
+                    DynamicImage::ImageLuma8(l) => {
+                        let (w, h) = l.dimensions();
+                        enc.write_image(l.as_raw(), w, h, image::ExtendedColorType::L8)
+                            .context("PNG encode failed")?;
+                    }
+                    _ if img.color().has_alpha() => {
+                        let rgba = img.to_rgba8();
+                        let (w, h) = rgba.dimensions();
+                        enc.write_image(rgba.as_raw(), w, h, image::ExtendedColorType::Rgba8)
+                            .context("PNG encode failed")?;
+                    }
+                    _ => {
+                        let rgb = img.to_rgb8();
+                        let (w, h) = rgb.dimensions();
+                        enc.write_image(rgb.as_raw(), w, h, image::ExtendedColorType::Rgb8)
+                            .context("PNG encode failed")?;
+                    }
+                }
+            }
+            OutFormat::Avif => {
+                // AVIF quality is 0-100 (higher = better quality, larger file),
+                // matching ravif's own scale, so it maps 1:1 onto the existing
+                // min/max quality range used by fit_quality's binary search.
+                // ravif accepts speeds 1..=10, so a speed of 0 maps to 1 (slowest).
+                let speed = avif_speed.clamp(1, 10);
+                let enc = ravif::Encoder::new()
+                    .with_quality(quality as f32)
+                    .with_speed(speed);
+                let encoded = if img.color().has_alpha() {
                     let rgba = img.to_rgba8();
                     let (w, h) = rgba.dimensions();
-                    let enc = PngEncoder::new_with_quality(
-                        &mut cursor,
-                        CompressionType::Level(level),
-                        PngFilterType::Adaptive,
-                    );
-                    enc.write_image(rgba.as_raw(), w, h, image::ExtendedColorType::Rgba8)
-                        .context("PNG encode failed")?;
+                    let pixels: Vec<ravif::RGBA8> = rgba
+                        .pixels()
+                        .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+                        .collect();
+                    enc.encode_rgba(ravif::Img::new(&pixels, w as usize, h as usize))
+                        .context("AVIF encode failed")?
                 } else {
                     let rgb = img.to_rgb8();
                     let (w, h) = rgb.dimensions();
-                    let enc = PngEncoder::new_with_quality(
-                        &mut cursor,
-                        CompressionType::Level(level),
-                        PngFilterType::Adaptive,
-                    );
-                    enc.write_image(rgb.as_raw(), w, h, image::ExtendedColorType::Rgb8)
-                        .context("PNG encode failed")?;
-                }
+                    let pixels: Vec<ravif::RGB8> = rgb
+                        .pixels()
+                        .map(|p| ravif::RGB8::new(p[0], p[1], p[2]))
+                        .collect();
+                    enc.encode_rgb(ravif::Img::new(&pixels, w as usize, h as usize))
+                        .context("AVIF encode failed")?
+                };
+                cursor
+                    .write_all(&encoded.avif_file)
+                    .context("AVIF encode failed")?;
             }
         }
     }
     Ok(buf)
 }
 
+/// Predicts the quality that lands just under `target_bytes`, given two
+/// probe encodes `(qmin, size_lo)` and `(qmax, size_hi)`. Models file size
+/// as roughly exponential in quality (linear in `log(size)` vs `quality`),
+/// which holds well enough for JPEG/WebP/AVIF's internal quantizers to beat
+/// pure bisection on the first guess.
+fn predict_quality(qmin: u8, size_lo: u64, qmax: u8, size_hi: u64, target_bytes: u64) -> u8 {
+    if qmax == qmin || size_lo == 0 || size_hi == 0 {
+        return qmin;
+    }
+    let log_lo = (size_lo as f64).ln();
+    let log_hi = (size_hi as f64).ln();
+    let slope = (log_hi - log_lo) / (qmax as f64 - qmin as f64);
+    if slope == 0.0 || !slope.is_finite() {
+        return qmin;
+    }
+    let target = (target_bytes as f64).ln();
+    let predicted = qmin as f64 + (target - log_lo) / slope;
+    predicted.round().clamp(qmin as f64, qmax as f64) as u8
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn fit_quality(
     img: &DynamicImage,
     fmt: OutFormat,
@@ -75,6 +189,10 @@ pub fn fit_quality(
     qmin: u8,
     qmax: u8,
     round: u8,
+    avif_speed: u8,
+    jpeg_encoder: JpegEncoderKind,
+    progressive: bool,
+    tolerance_bytes: u64,
 ) -> Result<(Vec<u8>, u8)> {
     if qmin > qmax || qmin == 0 || qmax > 100 {
         bail!("quality range must be within 1..=100 and min <= max");
@@ -89,41 +207,109 @@ pub fn fit_quality(
         target_bytes as f64 / 1024.0
     );
 
-    let mut lo = qmin as i32;
-    let mut hi = qmax as i32;
-
     let mut best: Option<(Vec<u8>, u8)> = None;
     let mut iteration = 0;
 
-    while lo <= hi {
+    let mut probe = |q: u8, best: &mut Option<(Vec<u8>, u8)>| -> Result<u64> {
         iteration += 1;
-        let mid = ((lo + hi) / 2) as u8;
-        let data = encode(img, fmt, mid)?;
+        let data = encode(img, fmt, q, avif_speed, jpeg_encoder, progressive)?;
         let size = data.len() as u64;
-
         eprint!(
             "    Iter {}: q={} -> {:.1}KB",
             iteration,
-            mid,
+            q,
             size as f64 / 1024.0
         );
+        if size <= target_bytes {
+            eprintln!(" ✓ (fits)");
+            let is_better = match best {
+                Some((_, best_q)) => q > *best_q,
+                None => true,
+            };
+            if is_better {
+                *best = Some((data, q));
+            }
+        } else {
+            eprintln!(" ✗ (too large)");
+        }
+        Ok(size)
+    };
+
+    // Seed the model with two probe encodes at the range's extremes.
+    let size_lo = probe(qmin, &mut best)?;
+    if qmax == qmin {
+        return match best {
+            Some(ok) => Ok(ok),
+            None => Ok((
+                encode(img, fmt, qmin, avif_speed, jpeg_encoder, progressive)?,
+                qmin,
+            )),
+        };
+    }
+    let size_hi = probe(qmax, &mut best)?;
+
+    // Binary search the *entire* remaining [qmin, qmax] range for the
+    // largest quality that still fits, provided encoded size is
+    // monotonically non-decreasing in quality -- true of every encoder used
+    // here. That invariant is what makes this provably find the optimum: a
+    // window bisected only around the log-linear model's prediction could
+    // miss the true answer on content where size-vs-quality isn't linear in
+    // log-space, since the model only picks where to *start* probing, not
+    // what the search is allowed to reach.
+    let predicted = predict_quality(qmin, size_lo, qmax, size_hi, target_bytes);
+    let mut lo = qmin as i32 + 1; // qmin already probed above
+    let mut hi = qmax as i32 - 1; // qmax already probed above
 
+    // Probe the prediction first so well-behaved (log-linear) content
+    // converges in very few further iterations, without narrowing the
+    // search space the rest of the bisection is allowed to cover.
+    if predicted as i32 > qmin as i32 && (predicted as i32) < qmax as i32 {
+        let size = probe(predicted, &mut best)?;
         if size <= target_bytes {
-            best = Some((data, mid));
-            eprintln!(" ✓ (fits, trying higher quality)");
+            if target_bytes - size > tolerance_bytes {
+                lo = predicted as i32 + 1; // try higher quality
+            } else {
+                lo = hi + 1; // within tolerance; skip the rest of the search
+                eprintln!(
+                    "    Within tolerance ({:.1}KB of target), stopping search early",
+                    (target_bytes - size) as f64 / 1024.0
+                );
+            }
+        } else {
+            hi = predicted as i32 - 1; // need smaller
+        }
+    }
+
+    while lo <= hi {
+        let mid = ((lo + hi) / 2) as u8;
+        let size = probe(mid, &mut best)?;
+        if size <= target_bytes {
+            if target_bytes - size <= tolerance_bytes {
+                eprintln!(
+                    "    Within tolerance ({:.1}KB of target), stopping search early",
+                    (target_bytes - size) as f64 / 1024.0
+                );
+                break;
+            }
             lo = mid as i32 + 1; // try higher quality
         } else {
-            eprintln!(" ✗ (too large, reducing quality)");
             hi = mid as i32 - 1; // need smaller
         }
     }
 
     // If nothing fits, return min quality result (caller may downscale)
-    if let Some(ok) = best {
-        Ok(ok)
+    if let Some((data, q)) = best {
+        eprintln!(
+            "  [Round {}] Achieved quality={} at {:.1}KB (target {:.1}KB)",
+            round,
+            q,
+            data.len() as f64 / 1024.0,
+            target_bytes as f64 / 1024.0
+        );
+        Ok((data, q))
     } else {
         eprintln!("    No quality fits, encoding at min quality for downscaling");
-        let data = encode(img, fmt, qmin)?;
+        let data = encode(img, fmt, qmin, avif_speed, jpeg_encoder, progressive)?;
         Ok((data, qmin))
     }
 }
@@ -132,6 +318,7 @@ pub fn apply_max_dimensions(
     mut img: DynamicImage,
     max_w: Option<u32>,
     max_h: Option<u32>,
+    filter: ResizeFilter,
 ) -> DynamicImage {
     if max_w.is_none() && max_h.is_none() {
         return img;
@@ -145,35 +332,289 @@ pub fn apply_max_dimensions(
     if scale < 1.0 {
         let new_w = (w as f32 * scale).max(1.0).round() as u32;
         let new_h = (h as f32 * scale).max(1.0).round() as u32;
-        img = img.resize(new_w, new_h, FilterType::Lanczos3);
+        img = crate::resize::resize_separable(&img, new_w, new_h, filter);
     }
     img
 }
 
-pub fn downscale_10_percent(img: &DynamicImage) -> DynamicImage {
+pub fn downscale_10_percent(img: &DynamicImage, filter: ResizeFilter) -> DynamicImage {
     let (w, h) = img.dimensions();
     let new_w = ((w as f32) * 0.9).floor().max(1.0) as u32;
     let new_h = ((h as f32) * 0.9).floor().max(1.0) as u32;
-    img.resize(new_w, new_h, FilterType::Lanczos3)
+    crate::resize::resize_separable(img, new_w, new_h, filter)
+}
+
+/// Reports whether every pixel has R==G==B, i.e. carries no color
+/// information. Fully transparent pixels are skipped since their RGB
+/// values are typically meaningless padding.
+pub fn is_grayscale(img: &DynamicImage) -> bool {
+    let rgba = img.to_rgba8();
+    rgba.pixels()
+        .all(|p| p[3] == 0 || (p[0] == p[1] && p[1] == p[2]))
+}
+
+fn apply_grayscale_mode(img: DynamicImage, mode: GrayscaleMode) -> DynamicImage {
+    let use_grayscale = match mode {
+        GrayscaleMode::Force => true,
+        GrayscaleMode::Off => false,
+        GrayscaleMode::Auto => is_grayscale(&img),
+    };
+    if !use_grayscale {
+        return img;
+    }
+    if img.color().has_alpha() {
+        DynamicImage::ImageLumaA8(img.to_luma_alpha8())
+    } else {
+        DynamicImage::ImageLuma8(img.to_luma8())
+    }
+}
+
+/// Reads the EXIF orientation tag (1-8) from the source file, if present.
+/// Returns `None` for sources with no EXIF data (PNG, GIF, ...) or no
+/// orientation tag, which is the common case and not an error.
+fn read_exif_orientation(input: &std::path::Path) -> Option<u32> {
+    let file = fs::File::open(input).ok()?;
+    let mut reader = std::io::BufReader::new(file);
+    let exif_data = exif::Reader::new()
+        .read_from_container(&mut reader)
+        .ok()?;
+    let field = exif_data.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+    field.value.get_uint(0)
+}
+
+/// Bakes the EXIF orientation into the pixel data so the image displays
+/// upright once metadata is stripped. Values per the EXIF spec; 1 means
+/// "already upright" and is handled by the caller skipping this entirely.
+fn apply_exif_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Reads the source's embedded ICC color profile, if any. `image::open`'s
+/// high-level decode path (used for pixels) doesn't surface this, so this
+/// re-opens the file through the lower-level decoder trait just for the
+/// profile bytes -- called only when `--keep-icc` is set.
+fn read_icc_profile(input: &std::path::Path) -> Option<Vec<u8>> {
+    let decoder = image::ImageReader::open(input)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    decoder.icc_profile().ok()?
+}
+
+/// Maximum ICC bytes per JPEG APP2 segment: the 2-byte length field caps a
+/// segment at 65535 bytes counting the length field itself, minus the
+/// 12-byte "ICC_PROFILE\0" marker signature and the 1-byte sequence number
+/// and 1-byte count.
+const JPEG_ICC_CHUNK_SIZE: usize = 65535 - 2 - 12 - 2;
+
+/// Embeds `icc` into a JPEG byte stream as one or more APP2 "ICC_PROFILE"
+/// marker segments immediately after the SOI marker, per the ICC spec's
+/// embedding convention for JPEG. Neither the mozjpeg nor the baseline
+/// `image` encoder used in `encode` exposes an ICC-embedding API, so this
+/// patches the already-encoded bytes instead.
+fn embed_jpeg_icc_profile(jpeg: Vec<u8>, icc: &[u8]) -> Vec<u8> {
+    if jpeg.len() < 2 || jpeg[0..2] != [0xFF, 0xD8] {
+        return jpeg; // not a JPEG SOI; leave untouched
+    }
+    let chunks: Vec<&[u8]> = if icc.is_empty() {
+        Vec::new()
+    } else {
+        icc.chunks(JPEG_ICC_CHUNK_SIZE).collect()
+    };
+    if chunks.is_empty() || chunks.len() > u8::MAX as usize {
+        return jpeg; // nothing to embed, or a profile too large to sequence
+    }
+    let total = chunks.len() as u8;
+
+    let mut out = Vec::with_capacity(jpeg.len() + icc.len() + chunks.len() * 18);
+    out.extend_from_slice(&jpeg[0..2]); // SOI
+    for (i, chunk) in chunks.iter().enumerate() {
+        let segment_len = 2 + 12 + 1 + 1 + chunk.len();
+        out.extend_from_slice(&[0xFF, 0xE2]); // APP2
+        out.extend_from_slice(&(segment_len as u16).to_be_bytes());
+        out.extend_from_slice(b"ICC_PROFILE\0");
+        out.push((i + 1) as u8);
+        out.push(total);
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&jpeg[2..]);
+    out
+}
+
+/// Adds an ICCP chunk, right after the VP8X header, to a WebP file from the
+/// `webp` crate's encoder, per the WebP RIFF container spec. A simple
+/// (non-extended) container is wrapped into an extended (VP8X) one; an
+/// already extended container (what the encoder writes for images with
+/// transparency) keeps its VP8X header with the ICC flag added.
+fn embed_webp_icc_profile(webp: Vec<u8>, icc: &[u8], width: u32, height: u32) -> Vec<u8> {
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" || icc.is_empty() {
+        return webp;
+    }
+    let mut vp8x_payload = [0u8; 10];
+    let original_chunk = if webp.len() >= 30 && &webp[12..16] == b"VP8X" {
+        vp8x_payload.copy_from_slice(&webp[20..30]);
+        vp8x_payload[0] |= 0x20; // bit 5: ICC profile present
+        &webp[30..]
+    } else {
+        vp8x_payload[0] = 0x20; // bit 5: ICC profile present
+        vp8x_payload[4..7].copy_from_slice(&width.saturating_sub(1).to_le_bytes()[0..3]);
+        vp8x_payload[7..10].copy_from_slice(&height.saturating_sub(1).to_le_bytes()[0..3]);
+        &webp[12..]
+    };
+
+    let mut out = Vec::with_capacity(webp.len() + icc.len() + 32);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&[0u8; 4]); // patched below once the final size is known
+    out.extend_from_slice(b"WEBP");
+
+    out.extend_from_slice(b"VP8X");
+    out.extend_from_slice(&(vp8x_payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&vp8x_payload);
+
+    out.extend_from_slice(b"ICCP");
+    out.extend_from_slice(&(icc.len() as u32).to_le_bytes());
+    out.extend_from_slice(icc);
+    if icc.len() % 2 == 1 {
+        out.push(0); // RIFF chunks are padded to an even length
+    }
+
+    out.extend_from_slice(original_chunk);
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    out
+}
+
+/// Applies `--keep-icc`: embeds `icc` into the encoded bytes for formats
+/// this crate knows how to patch an ICC profile into (JPEG, WebP), and
+/// returns `data` unchanged otherwise -- including for PNG/AVIF, which
+/// aren't supported here yet.
+fn apply_keep_icc(
+    data: Vec<u8>,
+    fmt: OutFormat,
+    icc: Option<&[u8]>,
+    dimensions: (u32, u32),
+) -> Vec<u8> {
+    let Some(icc) = icc else {
+        return data;
+    };
+    match fmt {
+        OutFormat::Jpeg => embed_jpeg_icc_profile(data, icc),
+        OutFormat::Webp => embed_webp_icc_profile(data, icc, dimensions.0, dimensions.1),
+        _ => data,
+    }
 }
 
 pub fn load_and_prepare_image(args: &Args) -> Result<DynamicImage> {
     let img = image::open(&args.input)
         .with_context(|| format!("failed to open input image: {:?}", args.input))?;
 
+    // Re-encoding always writes fresh output without EXIF/XMP (the encoders
+    // here write raw pixel buffers and never copy source metadata chunks;
+    // only an ICC profile is added back, under `--keep-icc`), so EXIF
+    // orientation must be baked into the pixels now or the result displays
+    // sideways once the tag is gone.
+    let img = match read_exif_orientation(&args.input) {
+        Some(orientation) if orientation != 1 => {
+            eprintln!("Auto-rotating for EXIF orientation {orientation}");
+            apply_exif_orientation(img, orientation)
+        }
+        _ => img,
+    };
+
     // Apply dimension constraints first
-    let img = apply_max_dimensions(img, args.max_width, args.max_height);
+    let img = apply_max_dimensions(img, args.max_width, args.max_height, args.filter);
+    let img = apply_grayscale_mode(img, args.grayscale);
 
     Ok(img)
 }
 
-pub fn pre_downscale_large_images(img: &mut DynamicImage, target_bytes: u64) {
-    // Pre-downscale very large images to speed up quality search
-    // Rough heuristic: WebP uses ~0.3-1 bytes per pixel depending on quality
-    // Use 2 bytes/pixel as safe upper bound for high quality
+/// Resolves `OutFormat::Auto` into a concrete format by inspecting the
+/// decoded image and the source file's extension: images with an alpha
+/// channel or a lossless source format are kept as PNG so screenshots and
+/// line art don't pick up JPEG artifacts, while photographic sources stay
+/// lossy to get the benefit of aggressive `--target-kb` compression.
+pub fn resolve_auto_format(input: &std::path::Path, img: &DynamicImage) -> OutFormat {
+    if img.color().has_alpha() {
+        return OutFormat::Png;
+    }
+    match image::ImageFormat::from_path(input).ok() {
+        Some(image::ImageFormat::Jpeg) => OutFormat::Jpeg,
+        Some(image::ImageFormat::WebP) => OutFormat::Webp,
+        Some(image::ImageFormat::Png | image::ImageFormat::Gif | image::ImageFormat::Bmp | image::ImageFormat::Tiff) => {
+            OutFormat::Png
+        }
+        _ => OutFormat::Webp,
+    }
+}
+
+/// Side length of the thumbnail probed in [`probe_bytes_per_pixel`]. Small
+/// enough that encoding it is negligible next to a full-size encode, large
+/// enough to capture whether the content is flat or busy.
+const PROBE_THUMBNAIL_DIM: u32 = 256;
+
+/// Measures actual bytes-per-pixel for this image/format by encoding a
+/// downscaled thumbnail at `qmin`, rather than assuming a single fixed
+/// bytes-per-pixel constant that can be far off for flat vs. busy content
+/// (the same mismatch [`predict_quality`] corrects for within a round, here
+/// applied to size the pre-downscale before the first round even starts).
+fn probe_bytes_per_pixel(
+    img: &DynamicImage,
+    fmt: OutFormat,
+    qmin: u8,
+    avif_speed: u8,
+    jpeg_encoder: JpegEncoderKind,
+    progressive: bool,
+    filter: ResizeFilter,
+) -> f64 {
+    let (w, h) = img.dimensions();
+    let thumb = if w > PROBE_THUMBNAIL_DIM || h > PROBE_THUMBNAIL_DIM {
+        let scale = (PROBE_THUMBNAIL_DIM as f32 / w.max(h) as f32).min(1.0);
+        let new_w = (w as f32 * scale).max(1.0).round() as u32;
+        let new_h = (h as f32 * scale).max(1.0).round() as u32;
+        crate::resize::resize_separable(img, new_w, new_h, filter)
+    } else {
+        img.clone()
+    };
+    let (tw, th) = thumb.dimensions();
+    match encode(&thumb, fmt, qmin, avif_speed, jpeg_encoder, progressive) {
+        Ok(data) if tw > 0 && th > 0 => data.len() as f64 / (tw as f64 * th as f64),
+        _ => 2.0, // fall back to the old fixed upper bound if the probe encode fails
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn pre_downscale_large_images(
+    img: &mut DynamicImage,
+    target_bytes: u64,
+    filter: ResizeFilter,
+    fmt: OutFormat,
+    qmin: u8,
+    avif_speed: u8,
+    jpeg_encoder: JpegEncoderKind,
+    progressive: bool,
+) {
+    // Pre-downscale very large images to speed up quality search. The
+    // bytes-per-pixel estimate comes from an actual probe encode rather
+    // than a fixed assumption, since that assumption can be off by several
+    // times depending on how busy the image content is.
     let (w, h) = img.dimensions();
     let current_pixels = (w as u64) * (h as u64);
-    let max_reasonable_pixels = target_bytes / 2; // 2 bytes per pixel upper bound
+    let bytes_per_pixel =
+        probe_bytes_per_pixel(img, fmt, qmin, avif_speed, jpeg_encoder, progressive, filter)
+            .max(0.05);
+    let max_reasonable_pixels = (target_bytes as f64 / bytes_per_pixel) as u64;
     if current_pixels > max_reasonable_pixels * 4 {
         // Image is way too large, pre-downscale to ~2x the estimated max
         let scale = ((max_reasonable_pixels * 2) as f64 / current_pixels as f64).sqrt();
@@ -183,7 +624,7 @@ pub fn pre_downscale_large_images(img: &mut DynamicImage, target_bytes: u64) {
             "Pre-downscaling from {}x{} to {}x{} (image too large for target)",
             w, h, new_w, new_h
         );
-        *img = img.resize(new_w, new_h, FilterType::Lanczos3);
+        *img = crate::resize::resize_separable(img, new_w, new_h, filter);
     }
 }
 
@@ -220,16 +661,273 @@ pub fn write_warning_output(
     );
 }
 
-pub fn process_png_compression(img: DynamicImage, args: &Args, target_bytes: u64) -> Result<()> {
+pub fn optimize_png(data: Vec<u8>, level: u8, zopfli: bool) -> Result<Vec<u8>> {
+    let mut options = oxipng::Options::from_preset(level.min(6));
+    if zopfli {
+        options.deflate = oxipng::Deflaters::Zopfli {
+            iterations: std::num::NonZeroU8::new(15).unwrap(),
+        };
+    }
+    oxipng::optimize_from_memory(&data, &options).context("oxipng optimization failed")
+}
+
+/// A `(rgba, count)` histogram entry: the unit the median-cut boxes operate
+/// on, instead of a raw per-pixel vector. Real photos rarely have more than a
+/// few hundred thousand distinct colors even at millions of pixels, so
+/// splitting boxes of histogram entries instead of boxes of pixels keeps
+/// `sort_unstable` (run per split, up to `max_colors` times, per step of
+/// `fit_png_colors`'s binary search) cheap regardless of image resolution.
+type ColorCount = ([u8; 4], u32);
+
+struct ColorBox {
+    colors: Vec<ColorCount>,
+}
+
+fn channel_range(colors: &[ColorCount], channel: usize) -> u8 {
+    let (min, max) = colors.iter().fold((255u8, 0u8), |(mn, mx), (p, _)| {
+        (mn.min(p[channel]), mx.max(p[channel]))
+    });
+    max - min
+}
+
+fn widest_channel(colors: &[ColorCount]) -> usize {
+    (0..4)
+        .max_by_key(|&c| channel_range(colors, c))
+        .unwrap_or(0)
+}
+
+fn split_box(b: ColorBox) -> (ColorBox, ColorBox) {
+    let channel = widest_channel(&b.colors);
+    let mut colors = b.colors;
+    colors.sort_unstable_by_key(|(p, _)| p[channel]);
+    let mid = colors.len() / 2;
+    let second = colors.split_off(mid);
+    (ColorBox { colors }, ColorBox { colors: second })
+}
+
+fn box_average(colors: &[ColorCount]) -> [u8; 4] {
+    let (mut r, mut g, mut b, mut a, mut total) = (0u64, 0u64, 0u64, 0u64, 0u64);
+    for (c, count) in colors {
+        let n = *count as u64;
+        r += c[0] as u64 * n;
+        g += c[1] as u64 * n;
+        b += c[2] as u64 * n;
+        a += c[3] as u64 * n;
+        total += n;
+    }
+    let total = total.max(1);
+    [
+        (r / total) as u8,
+        (g / total) as u8,
+        (b / total) as u8,
+        (a / total) as u8,
+    ]
+}
+
+/// Counts occurrences of each distinct RGBA value, so `build_palette` works
+/// over unique colors rather than every pixel.
+fn color_histogram(pixels: &[[u8; 4]]) -> Vec<ColorCount> {
+    let mut counts: std::collections::HashMap<[u8; 4], u32> = std::collections::HashMap::new();
+    for &p in pixels {
+        *counts.entry(p).or_insert(0) += 1;
+    }
+    counts.into_iter().collect()
+}
+
+/// Builds a palette of at most `max_colors` entries via median-cut: repeatedly
+/// split the box with the widest channel range at its median until the
+/// palette is full or no box can be split further. Operates on RGBA (not
+/// just RGB) so that images with varying transparency also end up with at
+/// most `max_colors` distinct `(r,g,b,a)` tuples, which is what lets
+/// `optimize_png`'s oxipng pass losslessly re-encode the result as a true
+/// indexed PNG instead of falling back to truecolor.
+fn build_palette(histogram: &[ColorCount], max_colors: u16) -> Vec<[u8; 4]> {
+    let mut boxes = vec![ColorBox {
+        colors: histogram.to_vec(),
+    }];
+
+    while boxes.len() < max_colors.max(1) as usize {
+        let Some((idx, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.colors.len() > 1)
+            .max_by_key(|(_, b)| channel_range(&b.colors, widest_channel(&b.colors)))
+        else {
+            break;
+        };
+        let target_box = boxes.remove(idx);
+        let (a, b) = split_box(target_box);
+        boxes.push(a);
+        boxes.push(b);
+    }
+
+    boxes.iter().map(|b| box_average(&b.colors)).collect()
+}
+
+fn nearest_in_palette(palette: &[[u8; 4]], target: [f32; 4]) -> [u8; 4] {
+    palette
+        .iter()
+        .min_by(|a, b| {
+            let dist = |c: &[u8; 4]| {
+                let dr = c[0] as f32 - target[0];
+                let dg = c[1] as f32 - target[1];
+                let db = c[2] as f32 - target[2];
+                let da = c[3] as f32 - target[3];
+                dr * dr + dg * dg + db * db + da * da
+            };
+            dist(a).partial_cmp(&dist(b)).unwrap()
+        })
+        .copied()
+        .unwrap_or([0, 0, 0, 255])
+}
+
+/// Reduces `img` to an indexed-style palette of at most `max_colors` distinct
+/// RGBA values via median-cut quantization over a color histogram (not the
+/// raw per-pixel buffer), optionally dithering with Floyd-Steinberg error
+/// diffusion. Resolution is preserved; alpha is quantized onto the same
+/// palette as RGB (rather than passed through unchanged) so that the output
+/// never has more than `max_colors` distinct `(r,g,b,a)` tuples regardless of
+/// how much the source alpha varies.
+///
+/// This crate has no direct PNG-palette-chunk writer (the `image` encoder
+/// used in `encode` only exposes truecolor/grayscale color types), so we
+/// don't emit the indexed PNG ourselves. Instead we rely on `optimize_png`'s
+/// oxipng pass, which always runs and losslessly rewrites any buffer with
+/// `<= 256` distinct colors as a real indexed PNG — quantizing alpha here is
+/// what makes that guaranteed to apply to RGBA sources too, not just RGB.
+pub fn quantize(img: &DynamicImage, max_colors: u16, dither: bool) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+
+    let rgba_pixels: Vec<[u8; 4]> = rgba.pixels().map(|p| p.0).collect();
+    let palette = build_palette(&color_histogram(&rgba_pixels), max_colors);
+
+    let mut out = rgba.clone();
+
+    if dither {
+        let mut errors = vec![[0f32; 4]; rgba_pixels.len()];
+        for y in 0..h as usize {
+            for x in 0..w as usize {
+                let idx = y * w as usize + x;
+                let px = rgba_pixels[idx];
+                let adjusted = [
+                    (px[0] as f32 + errors[idx][0]).clamp(0.0, 255.0),
+                    (px[1] as f32 + errors[idx][1]).clamp(0.0, 255.0),
+                    (px[2] as f32 + errors[idx][2]).clamp(0.0, 255.0),
+                    (px[3] as f32 + errors[idx][3]).clamp(0.0, 255.0),
+                ];
+                let nearest = nearest_in_palette(&palette, adjusted);
+                let err = [
+                    adjusted[0] - nearest[0] as f32,
+                    adjusted[1] - nearest[1] as f32,
+                    adjusted[2] - nearest[2] as f32,
+                    adjusted[3] - nearest[3] as f32,
+                ];
+                out.put_pixel(x as u32, y as u32, image::Rgba(nearest));
+
+                let mut diffuse = |x: usize, y: usize, frac: f32| {
+                    if x < w as usize && y < h as usize {
+                        let i = y * w as usize + x;
+                        errors[i][0] += err[0] * frac;
+                        errors[i][1] += err[1] * frac;
+                        errors[i][2] += err[2] * frac;
+                        errors[i][3] += err[3] * frac;
+                    }
+                };
+                diffuse(x + 1, y, 7.0 / 16.0);
+                if x > 0 {
+                    diffuse(x - 1, y + 1, 3.0 / 16.0);
+                }
+                diffuse(x, y + 1, 5.0 / 16.0);
+                diffuse(x + 1, y + 1, 1.0 / 16.0);
+            }
+        }
+    } else {
+        for (idx, px) in rgba_pixels.iter().enumerate() {
+            let nearest = nearest_in_palette(
+                &palette,
+                [px[0] as f32, px[1] as f32, px[2] as f32, px[3] as f32],
+            );
+            let x = (idx % w as usize) as u32;
+            let y = (idx / w as usize) as u32;
+            out.put_pixel(x, y, image::Rgba(nearest));
+        }
+    }
+
+    if has_alpha {
+        DynamicImage::ImageRgba8(out)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(out).to_rgb8())
+    }
+}
+
+/// Binary-searches the palette size in `[2, max_colors]`, analogous to
+/// `fit_quality`'s quality search: fewer colors shrink the file, so we look
+/// for the largest palette that still fits under `target_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn fit_png_colors(
+    img: &DynamicImage,
+    target_bytes: u64,
+    max_colors: u16,
+    dither: bool,
+    png_compression_level: u8,
+    avif_speed: u8,
+    oxipng_level: u8,
+    zopfli: bool,
+    jpeg_encoder: JpegEncoderKind,
+    progressive: bool,
+) -> Result<Option<(Vec<u8>, u16)>> {
+    let mut lo = 2i32;
+    let mut hi = max_colors.max(2) as i32;
+    let mut best: Option<(Vec<u8>, u16)> = None;
+
+    while lo <= hi {
+        let mid = ((lo + hi) / 2) as u16;
+        let quantized = quantize(img, mid, dither);
+        let data = encode(
+            &quantized,
+            OutFormat::Png,
+            png_compression_level,
+            avif_speed,
+            jpeg_encoder,
+            progressive,
+        )?;
+        let data = optimize_png(data, oxipng_level, zopfli)?;
+
+        if (data.len() as u64) <= target_bytes {
+            best = Some((data, mid));
+            lo = mid as i32 + 1; // try a larger palette while it still fits
+        } else {
+            hi = mid as i32 - 1;
+        }
+    }
+
+    Ok(best)
+}
+
+/// Runs the PNG compression loop and returns a short description of the
+/// settings that produced the final output (e.g. `"compression_level=6"`),
+/// which batch mode reports alongside each file's before/after size.
+pub fn process_png_compression(img: DynamicImage, args: &Args, target_bytes: u64) -> Result<String> {
     let mut current_img = img;
     for round in 0..=args.max_downscale_rounds {
-        let data = encode(&current_img, OutFormat::Png, args.png_compression_level)?;
+        let data = encode(
+            &current_img,
+            OutFormat::Png,
+            args.png_compression_level,
+            args.avif_speed,
+            args.jpeg_encoder,
+            args.progressive,
+        )?;
+        let data = optimize_png(data, args.oxipng_level, args.zopfli)?;
         let size = data.len() as u64;
 
         if round == 0 {
             eprintln!(
-                "  [PNG] Encoding at compression level {}, initial size {:.1}KB",
+                "  [PNG] Encoding at compression level {} (oxipng level {}), initial size {:.1}KB",
                 args.png_compression_level,
+                args.oxipng_level,
                 size as f64 / 1024.0
             );
         } else {
@@ -254,7 +952,38 @@ pub fn process_png_compression(img: DynamicImage, args: &Args, target_bytes: u64
                 args.format,
                 &compression_info,
             );
-            return Ok(());
+            return Ok(compression_info);
+        }
+
+        if let Some((quantized_data, colors)) = fit_png_colors(
+            &current_img,
+            target_bytes,
+            args.png_max_colors,
+            args.dither,
+            args.png_compression_level,
+            args.avif_speed,
+            args.oxipng_level,
+            args.zopfli,
+            args.jpeg_encoder,
+            args.progressive,
+        )? {
+            fs::write(&args.output, &quantized_data)
+                .with_context(|| format!("failed to write output: {:?}", args.output))?;
+
+            let dimensions = current_img.dimensions();
+            let compression_info = format!(
+                "compression_level={} max_colors={}",
+                args.png_compression_level, colors
+            );
+            write_success_output(
+                &args.input,
+                &args.output,
+                &quantized_data,
+                dimensions,
+                args.format,
+                &compression_info,
+            );
+            return Ok(compression_info);
         }
 
         if round == args.max_downscale_rounds {
@@ -270,20 +999,42 @@ pub fn process_png_compression(img: DynamicImage, args: &Args, target_bytes: u64
                 args.format,
                 &compression_info,
             );
-            return Ok(());
+            return Ok(compression_info);
         }
 
-        current_img = downscale_10_percent(&current_img);
+        current_img = downscale_10_percent(&current_img, args.filter);
     }
-    Ok(())
+    Ok(String::new())
 }
 
+/// Runs the quality-search/downscale loop for lossy formats and returns a
+/// short description of the settings that produced the final output (e.g.
+/// `"quality=83"`), which batch mode reports alongside each file's
+/// before/after size.
 pub fn process_lossy_compression(
     mut img: DynamicImage,
     args: &Args,
     target_bytes: u64,
-) -> Result<()> {
-    pre_downscale_large_images(&mut img, target_bytes);
+) -> Result<String> {
+    pre_downscale_large_images(
+        &mut img,
+        target_bytes,
+        args.filter,
+        args.format,
+        args.min_quality,
+        args.avif_speed,
+        args.jpeg_encoder,
+        args.progressive,
+    );
+
+    // Read once up front rather than per quality-search probe: only the
+    // final chosen encoding ever gets written, so there's no benefit to
+    // embedding it into bytes that get thrown away.
+    let icc = if args.keep_icc {
+        read_icc_profile(&args.input)
+    } else {
+        None
+    };
 
     let mut last_data = Vec::new();
     let mut last_q = args.min_quality;
@@ -296,40 +1047,46 @@ pub fn process_lossy_compression(
             args.min_quality,
             args.max_quality,
             round,
+            args.avif_speed,
+            args.jpeg_encoder,
+            args.progressive,
+            args.tolerance_kb.unwrap_or(0) * 1024,
         )?;
 
         last_data = data;
         last_q = q;
 
         if (last_data.len() as u64) <= target_bytes {
-            fs::write(&args.output, &last_data)
+            let dimensions = img.dimensions();
+            let final_data = apply_keep_icc(last_data, args.format, icc.as_deref(), dimensions);
+            fs::write(&args.output, &final_data)
                 .with_context(|| format!("failed to write output: {:?}", args.output))?;
 
-            let dimensions = img.dimensions();
             let compression_info = format!("quality={}", last_q);
             write_success_output(
                 &args.input,
                 &args.output,
-                &last_data,
+                &final_data,
                 dimensions,
                 args.format,
                 &compression_info,
             );
-            return Ok(());
+            return Ok(compression_info);
         }
 
         if round == args.max_downscale_rounds {
             break;
         }
         eprintln!("  → Downscaling by 10% and retrying...");
-        img = downscale_10_percent(&img);
+        img = downscale_10_percent(&img, args.filter);
     }
 
     // Write best-effort output
+    let dimensions = img.dimensions();
+    let last_data = apply_keep_icc(last_data, args.format, icc.as_deref(), dimensions);
     fs::write(&args.output, &last_data)
         .with_context(|| format!("failed to write output: {:?}", args.output))?;
 
-    let dimensions = img.dimensions();
     let compression_info = format!("quality={}", last_q);
     write_warning_output(
         (last_data.len() as f64) / 1024.0,
@@ -339,5 +1096,5 @@ pub fn process_lossy_compression(
         &compression_info,
     );
 
-    Ok(())
+    Ok(compression_info)
 }
@@ -0,0 +1,208 @@
+//! Separable-convolution image resizing with precomputed, cached
+//! per-axis coefficients.
+//!
+//! `image::imageops::resize` recomputes its filter weights from scratch on
+//! every call. Here the weights for each `(src_len, dst_len, filter)` triple
+//! are cached for the life of the process, so they are computed once and
+//! reused whenever the same triple recurs: the two axes of a square image,
+//! or same-sized source files in batch mode going through the same
+//! `--max-width`/`--max-height` resize and the same downscale rounds. Each
+//! round of the downscale loop in `processor::process_lossy_compression`
+//! shrinks the image to a new size, so successive rounds of one file do not
+//! share coefficients.
+//!
+//! This crate has no Cargo.toml to add a SIMD crate to, and `std::simd` is
+//! nightly-only, so there's no explicit intrinsics fast path here. The
+//! per-axis loops below are scalar `f32` accumulations: the horizontal pass
+//! reads source pixels through `get_pixel`, and the vertical pass reads the
+//! intermediate buffer one row apart per tap. Any vectorization is left to
+//! the compiler.
+
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::cli::ResizeFilter;
+
+fn filter_support(filter: ResizeFilter) -> f32 {
+    match filter {
+        ResizeFilter::Nearest => 0.5,
+        ResizeFilter::Triangle => 1.0,
+        ResizeFilter::CatmullRom => 2.0,
+        ResizeFilter::Gaussian => 2.0,
+        ResizeFilter::Lanczos3 => 3.0,
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-8 {
+        1.0
+    } else {
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+fn catmull_rom(x: f32) -> f32 {
+    let x = x.abs();
+    if x < 1.0 {
+        ((1.5 * x - 2.5) * x) * x + 1.0
+    } else if x < 2.0 {
+        (((-0.5 * x + 2.5) * x - 4.0) * x) + 2.0
+    } else {
+        0.0
+    }
+}
+
+fn gaussian(x: f32) -> f32 {
+    const SIGMA: f32 = 0.8;
+    (-(x * x) / (2.0 * SIGMA * SIGMA)).exp()
+}
+
+fn filter_weight(filter: ResizeFilter, x: f32) -> f32 {
+    match filter {
+        ResizeFilter::Nearest => {
+            if x.abs() < 0.5 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        ResizeFilter::Triangle => (1.0 - x.abs()).max(0.0),
+        ResizeFilter::CatmullRom => catmull_rom(x),
+        ResizeFilter::Gaussian => gaussian(x),
+        ResizeFilter::Lanczos3 => {
+            if x.abs() < 3.0 {
+                sinc(x) * sinc(x / 3.0)
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+/// One output pixel's contribution: the first source index it reads from,
+/// and the normalized weights applied starting there.
+type AxisWeights = Vec<(i64, Vec<f32>)>;
+
+/// Computes, for one axis, the precomputed filter weights mapping `src_len`
+/// source samples onto `dst_len` output samples. When downscaling, the
+/// filter support is widened by the scale factor to prefilter and avoid
+/// aliasing, matching the standard resampling algorithm.
+fn compute_axis_weights(src_len: u32, dst_len: u32, filter: ResizeFilter) -> AxisWeights {
+    let scale = src_len as f32 / dst_len as f32;
+    let filter_scale = scale.max(1.0);
+    let support = filter_support(filter) * filter_scale;
+
+    (0..dst_len)
+        .map(|dst_x| {
+            let center = (dst_x as f32 + 0.5) * scale - 0.5;
+            let left = (center - support).floor() as i64;
+            let right = (center + support).ceil() as i64;
+
+            let mut weights: Vec<f32> = (left..=right)
+                .map(|i| filter_weight(filter, (i as f32 - center) / filter_scale))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() > 1e-8 {
+                for w in &mut weights {
+                    *w /= sum;
+                }
+            }
+            (left, weights)
+        })
+        .collect()
+}
+
+type AxisWeightsCache = Mutex<HashMap<(u32, u32, u8), Arc<AxisWeights>>>;
+
+fn cached_axis_weights(src_len: u32, dst_len: u32, filter: ResizeFilter) -> Arc<AxisWeights> {
+    static CACHE: OnceLock<AxisWeightsCache> = OnceLock::new();
+    let cache = CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+
+    let key = (src_len, dst_len, filter as u8);
+    let mut guard = cache.lock().unwrap();
+    if let Some(weights) = guard.get(&key) {
+        return weights.clone();
+    }
+    let weights = Arc::new(compute_axis_weights(src_len, dst_len, filter));
+    guard.insert(key, weights.clone());
+    weights
+}
+
+/// Resizes `img` to exactly `new_w`x`new_h` via separable convolution,
+/// reusing cached per-axis coefficients for any `(src_len, dst_len, filter)`
+/// triple seen before in this process (e.g. across same-sized files in batch
+/// mode). `Nearest` skips the convolution and samples directly.
+pub fn resize_separable(img: &DynamicImage, new_w: u32, new_h: u32, filter: ResizeFilter) -> DynamicImage {
+    let (w, h) = img.dimensions();
+    if w == 0 || h == 0 || new_w == 0 || new_h == 0 || (w, h) == (new_w, new_h) {
+        return img.clone();
+    }
+    if filter == ResizeFilter::Nearest {
+        // A convolution pass buys nothing over direct nearest-neighbor sampling.
+        return img.resize_exact(new_w, new_h, image::imageops::FilterType::Nearest);
+    }
+
+    let is_luma = matches!(
+        img,
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_)
+    );
+    let has_alpha = img.color().has_alpha();
+    let rgba = img.to_rgba8();
+
+    let h_weights = cached_axis_weights(w, new_w, filter);
+    let v_weights = cached_axis_weights(h, new_h, filter);
+
+    // Horizontal pass: (w, h) -> (new_w, h), accumulated in f32 for precision.
+    let mut mid = vec![0f32; new_w as usize * h as usize * 4];
+    for y in 0..h as usize {
+        for (dst_x, (start, weights)) in h_weights.iter().enumerate() {
+            let mut acc = [0f32; 4];
+            for (k, wt) in weights.iter().enumerate() {
+                let sx = (*start + k as i64).clamp(0, w as i64 - 1) as u32;
+                let p = rgba.get_pixel(sx, y as u32);
+                for c in 0..4 {
+                    acc[c] += p.0[c] as f32 * wt;
+                }
+            }
+            let base = (y * new_w as usize + dst_x) * 4;
+            mid[base..base + 4].copy_from_slice(&acc);
+        }
+    }
+
+    // Vertical pass: (new_w, h) -> (new_w, new_h).
+    let mut out = RgbaImage::new(new_w, new_h);
+    for x in 0..new_w as usize {
+        for (dst_y, (start, weights)) in v_weights.iter().enumerate() {
+            let mut acc = [0f32; 4];
+            for (k, wt) in weights.iter().enumerate() {
+                let sy = (*start + k as i64).clamp(0, h as i64 - 1) as usize;
+                let base = (sy * new_w as usize + x) * 4;
+                for c in 0..4 {
+                    acc[c] += mid[base + c] * wt;
+                }
+            }
+            let px = [
+                acc[0].round().clamp(0.0, 255.0) as u8,
+                acc[1].round().clamp(0.0, 255.0) as u8,
+                acc[2].round().clamp(0.0, 255.0) as u8,
+                acc[3].round().clamp(0.0, 255.0) as u8,
+            ];
+            out.put_pixel(x as u32, dst_y as u32, Rgba(px));
+        }
+    }
+
+    let result = DynamicImage::ImageRgba8(out);
+    if is_luma {
+        if has_alpha {
+            DynamicImage::ImageLumaA8(result.to_luma_alpha8())
+        } else {
+            DynamicImage::ImageLuma8(result.to_luma8())
+        }
+    } else if has_alpha {
+        result
+    } else {
+        DynamicImage::ImageRgb8(result.to_rgb8())
+    }
+}